@@ -1,12 +1,17 @@
+use std::borrow::Cow;
 use std::error::Error;
-use std::io::{BufRead, BufReader, Cursor, ErrorKind, Lines, Read, Write};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Cursor, ErrorKind, Lines, Read, Write};
 use std::mem::swap;
-use std::net::TcpStream;
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
 
 use embedded_graphics::image::{Image, ImageRaw};
-use embedded_graphics::pixelcolor::BinaryColor;
+use embedded_graphics::pixelcolor::{BinaryColor, Gray4};
 use embedded_graphics::{
     mono_font::{ascii::*, MonoTextStyle},
     pixelcolor::Rgb888,
@@ -15,24 +20,530 @@ use embedded_graphics::{
 use embedded_graphics_simulator::{
     BinaryColorTheme, OutputSettingsBuilder, SimulatorDisplay, SimulatorEvent,
 };
+use clap::Parser;
 use gdb_protocol::io::GdbServer;
 use gdb_protocol::packet::{CheckedPacket, Kind};
+use gif::{Encoder, Frame, Repeat};
 
-const ELF_PATH : &str = "C:/Users/Kate/GitHub/DelugeFirmware/dbt-build-debug-oled/Deluge-debug-oled.elf";
+// Scale factor applied to the logical display when building the simulator window.
+const SCALE: u32 = 4;
 
-fn find_debug_symbol() -> Result<u32, ErrorKind> {
+/// The embedded-graphics pixel type a [`FramebufferFormat`] decodes into, which
+/// selects both the render path and the GIF palette.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum TargetFormat {
+    BinaryColor,
+    Gray4,
+}
+
+/// Unpacks a controller's raw framebuffer into a render-ready buffer. The monochrome
+/// formats produce an MSB-first row-packed 1-bit buffer suitable for
+/// `ImageRaw::<BinaryColor>`; the grayscale formats expand packed nibbles into a
+/// byte-per-pixel buffer rendered against a `Gray4` target.
+trait FramebufferFormat {
+    /// Number of source bytes the controller packs for a display of `size`.
+    fn src_len(&self, size: Size) -> usize;
+
+    /// Number of destination bytes the decoded buffer occupies.
+    fn dst_len(&self, size: Size) -> usize;
+
+    /// The pixel format the decoded buffer is rendered as.
+    fn target(&self) -> TargetFormat;
+
+    /// Unpack `src` into the render-ready `dst` buffer for a display of `size`.
+    fn decode(&self, src: &[u8], size: Size, dst: &mut [u8]);
+}
+
+/// Number of bytes per row once a 1-bit-per-pixel buffer is padded to a byte boundary.
+fn row_bytes(width: usize) -> usize {
+    (width + 7) / 8
+}
+
+/// The SSD1306/SSD1309 column-first, page-packed layout used by the Deluge: the LSB
+/// of byte 0 is (0,0), the MSB is (0,7), byte 1 is (1, x), etc.
+struct Ssd1306Page;
+
+impl FramebufferFormat for Ssd1306Page {
+    fn src_len(&self, size: Size) -> usize {
+        (size.width * (size.height >> 3)) as usize
+    }
+
+    fn dst_len(&self, size: Size) -> usize {
+        row_bytes(size.width as usize) * size.height as usize
+    }
+
+    fn target(&self) -> TargetFormat {
+        TargetFormat::BinaryColor
+    }
+
+    fn decode(&self, src: &[u8], size: Size, dst: &mut [u8]) {
+        let width = size.width as usize;
+        let stride = row_bytes(width);
+        for (page_y, row) in src.chunks(width).enumerate() {
+            for (x, col) in row.iter().enumerate() {
+                for bit in 0..8 {
+                    let y = (page_y * 8) + bit;
+                    let bitmask = 1u8 << (7 - (x % 8));
+                    if let Some(slot) = dst.get_mut(y * stride + x / 8) {
+                        if (col >> bit) & 0b1 == 1 {
+                            *slot |= bitmask
+                        } else {
+                            *slot &= !bitmask
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Row-packed 1-bit layout where the MSB of each byte is the left-most pixel. This is
+/// already the order `ImageRaw::<BinaryColor>` expects, so decoding is a straight copy.
+struct HorizontalMsbFirst;
+
+impl FramebufferFormat for HorizontalMsbFirst {
+    fn src_len(&self, size: Size) -> usize {
+        row_bytes(size.width as usize) * size.height as usize
+    }
+
+    fn dst_len(&self, size: Size) -> usize {
+        self.src_len(size)
+    }
+
+    fn target(&self) -> TargetFormat {
+        TargetFormat::BinaryColor
+    }
+
+    fn decode(&self, src: &[u8], _size: Size, dst: &mut [u8]) {
+        let len = dst.len().min(src.len());
+        dst[..len].copy_from_slice(&src[..len]);
+        // Clear any tail a short GDB read left untouched so stale pixels don't linger.
+        dst[len..].fill(0);
+    }
+}
+
+/// Row-packed 1-bit layout where the LSB of each byte is the left-most pixel; the bit
+/// order within every byte is reversed relative to [`HorizontalMsbFirst`].
+struct HorizontalLsbFirst;
+
+impl FramebufferFormat for HorizontalLsbFirst {
+    fn src_len(&self, size: Size) -> usize {
+        row_bytes(size.width as usize) * size.height as usize
+    }
+
+    fn dst_len(&self, size: Size) -> usize {
+        self.src_len(size)
+    }
+
+    fn target(&self) -> TargetFormat {
+        TargetFormat::BinaryColor
+    }
+
+    fn decode(&self, src: &[u8], size: Size, dst: &mut [u8]) {
+        let width = size.width as usize;
+        let stride = row_bytes(width);
+        for y in 0..size.height as usize {
+            for x in 0..width {
+                let src_idx = y * stride + x / 8;
+                let bit = (src.get(src_idx).copied().unwrap_or(0) >> (x % 8)) & 0b1;
+                let dst_idx = y * stride + x / 8;
+                let bitmask = 1u8 << (7 - (x % 8));
+                if bit == 1 {
+                    dst[dst_idx] |= bitmask
+                } else {
+                    dst[dst_idx] &= !bitmask
+                }
+            }
+        }
+    }
+}
+
+/// Packed 2-bit grayscale, four pixels per byte, most-significant pixel first. Each
+/// value is expanded to a byte in the `Gray4` 0..=15 range.
+struct R2;
+
+impl FramebufferFormat for R2 {
+    fn src_len(&self, size: Size) -> usize {
+        (size.width as usize * size.height as usize).div_ceil(4)
+    }
+
+    fn dst_len(&self, size: Size) -> usize {
+        size.width as usize * size.height as usize
+    }
+
+    fn target(&self) -> TargetFormat {
+        TargetFormat::Gray4
+    }
+
+    fn decode(&self, src: &[u8], _size: Size, dst: &mut [u8]) {
+        for (i, px) in dst.iter_mut().enumerate() {
+            let byte = src.get(i / 4).copied().unwrap_or(0);
+            let shift = 6 - 2 * (i % 4);
+            // Scale the 2-bit value (0..=3) up into the Gray4 range (0, 5, 10, 15).
+            *px = ((byte >> shift) & 0b11) * 5;
+        }
+    }
+}
+
+/// Packed 4-bit grayscale, two pixels per byte, high nibble first. Each nibble maps
+/// directly onto a `Gray4` value.
+struct R4;
+
+impl FramebufferFormat for R4 {
+    fn src_len(&self, size: Size) -> usize {
+        (size.width as usize * size.height as usize).div_ceil(2)
+    }
+
+    fn dst_len(&self, size: Size) -> usize {
+        size.width as usize * size.height as usize
+    }
+
+    fn target(&self) -> TargetFormat {
+        TargetFormat::Gray4
+    }
+
+    fn decode(&self, src: &[u8], _size: Size, dst: &mut [u8]) {
+        for (i, px) in dst.iter_mut().enumerate() {
+            let byte = src.get(i / 2).copied().unwrap_or(0);
+            *px = if i % 2 == 0 { byte >> 4 } else { byte & 0x0f };
+        }
+    }
+}
+
+/// Resolve a `--format` argument into its decoder. The default `ssd1306` selects the
+/// Deluge's native SSD1309 layout; an unrecognised name is a hard error.
+fn parse_format(name: &str) -> Box<dyn FramebufferFormat + Send> {
+    match name {
+        "ssd1306" => Box::new(Ssd1306Page),
+        "horizontal-msb" => Box::new(HorizontalMsbFirst),
+        "horizontal-lsb" => Box::new(HorizontalLsbFirst),
+        "r2" => Box::new(R2),
+        "r4" => Box::new(R4),
+        other => panic!("unknown --format '{other}'"),
+    }
+}
+
+/// Command-line configuration. Everything that used to be a compile-time constant — the
+/// ELF, the `nm` binary, the GDB endpoint, the geometry and the refresh rate — is now an
+/// option, and `--display` may be repeated to mirror several framebuffers at once.
+#[derive(Parser)]
+#[command(about = "Mirror a firmware framebuffer read over GDB into a simulator window")]
+struct Args {
+    /// Path to the firmware ELF to resolve framebuffer symbols from
+    #[arg(long, default_value = "C:/Users/Kate/GitHub/DelugeFirmware/dbt-build-debug-oled/Deluge-debug-oled.elf")]
+    elf: String,
+
+    /// `nm` binary used to look up symbol addresses
+    #[arg(long, default_value = "arm-none-eabi-nm")]
+    nm: String,
+
+    /// GDB/OpenOCD endpoint to connect to
+    #[arg(long, default_value = "127.0.0.1:3333")]
+    gdb: String,
+
+    /// Desired refresh rate in Hz
+    #[arg(long, default_value_t = 24.0)]
+    frequency: f64,
+
+    /// Encode every rendered frame of the primary display into an animated GIF
+    #[arg(long)]
+    record: Option<String>,
+
+    /// Serve the primary display's frames to TCP subscribers on this `ADDR`
+    #[arg(long, value_name = "ADDR")]
+    serve: Option<String>,
+
+    /// Append every captured raw framebuffer of the primary display (with a timestamp) to
+    /// this file for later replay
+    #[arg(long, value_name = "FILE")]
+    dump: Option<String>,
+
+    /// Replay a previously dumped file instead of connecting to GDB, honoring its timing
+    #[arg(long, value_name = "FILE")]
+    replay: Option<String>,
+
+    /// A display to mirror, as `SYMBOL,WIDTHxHEIGHT[,FORMAT]`. Repeat to mirror several
+    /// framebuffers (e.g. a main OLED and a secondary status display) in separate
+    /// windows from one session.
+    #[arg(long = "display", value_name = "SPEC")]
+    displays: Vec<String>,
+}
+
+/// A single framebuffer to mirror: where to find it and how to unpack it.
+struct DisplaySpec {
+    symbol: String,
+    size: Size,
+    format: Box<dyn FramebufferFormat + Send>,
+}
+
+/// Parse a `SYMBOL,WIDTHxHEIGHT[,FORMAT]` spec. A comma separator keeps C++ symbol
+/// names (which contain `::`) intact; the format defaults to `ssd1306`.
+fn parse_display_spec(spec: &str) -> DisplaySpec {
+    let mut parts = spec.split(',');
+    let symbol = parts.next().expect("display spec is empty").to_string();
+    let dims = parts.next().expect("display spec needs a WIDTHxHEIGHT geometry");
+    let (w, h) = dims
+        .split_once('x')
+        .expect("geometry must look like 128x48");
+    let size = Size::new(
+        w.parse().expect("invalid display width"),
+        h.parse().expect("invalid display height"),
+    );
+    let format = parse_format(parts.next().unwrap_or("ssd1306"));
+    DisplaySpec { symbol, size, format }
+}
+
+/// A live simulator display, monomorphised over the target pixel type so a single event
+/// loop can drive a mix of monochrome and grayscale windows.
+enum LiveDisplay {
+    Binary(SimulatorDisplay<BinaryColor>),
+    Gray(SimulatorDisplay<Gray4>),
+}
+
+impl LiveDisplay {
+    fn new(target: TargetFormat, size: Size) -> Self {
+        match target {
+            TargetFormat::BinaryColor => LiveDisplay::Binary(SimulatorDisplay::new(size)),
+            TargetFormat::Gray4 => LiveDisplay::Gray(SimulatorDisplay::new(size)),
+        }
+    }
+
+    /// Draw a decoded frame onto the backing display.
+    fn draw(&mut self, size: Size, buf: &[u8]) {
+        match self {
+            LiveDisplay::Binary(display) => {
+                let raw_image = ImageRaw::<BinaryColor>::new(buf, size.width);
+                Image::new(&raw_image, Point::zero()).draw(display).unwrap();
+            }
+            LiveDisplay::Gray(display) => {
+                let width = size.width as usize;
+                display
+                    .draw_iter(buf.iter().enumerate().map(|(i, &v)| {
+                        Pixel(Point::new((i % width) as i32, (i / width) as i32), Gray4::new(v))
+                    }))
+                    .unwrap();
+            }
+        }
+    }
+
+    /// Push the backing display to its window.
+    fn update(&self, window: &mut embedded_graphics_simulator::Window) {
+        match self {
+            LiveDisplay::Binary(display) => window.update(display),
+            LiveDisplay::Gray(display) => window.update(display),
+        }
+    }
+}
+
+/// Encodes successive logical frames into an animated GIF, scaling each decoded frame
+/// up to the simulator window size so clips match what is on screen.
+struct GifRecorder {
+    encoder: Encoder<BufWriter<File>>,
+    size: Size,
+    target: TargetFormat,
+    delay: u16,
+}
+
+impl GifRecorder {
+    /// Open `path` for writing and install a palette matching `target`: the two-colour
+    /// `OledWhite` pair for `BinaryColor`, or a 16-step grayscale ramp for `Gray4`. The
+    /// `delay` is in hundredths of a second so playback matches the render rate.
+    fn new(path: &str, size: Size, target: TargetFormat, delay: u16) -> Result<Self, Box<dyn Error>> {
+        let width = (size.width * SCALE) as u16;
+        let height = (size.height * SCALE) as u16;
+        let palette = match target {
+            // The `OledWhite` theme renders "off" pixels near-black and "on" near-white.
+            TargetFormat::BinaryColor => vec![0x0a, 0x0a, 0x0a, 0xf5, 0xf5, 0xf5],
+            TargetFormat::Gray4 => (0..16u8).flat_map(|v| [v * 17, v * 17, v * 17]).collect(),
+        };
+        let mut encoder = Encoder::new(BufWriter::new(File::create(path)?), width, height, &palette)?;
+        encoder.set_repeat(Repeat::Infinite)?;
+        Ok(Self { encoder, size, target, delay })
+    }
+
+    /// Write one frame, resolving each logical pixel to a palette index and scaling it
+    /// into a `SCALE`×`SCALE` block.
+    fn push(&mut self, buf: &[u8]) -> Result<(), Box<dyn Error>> {
+        let width = self.size.width as usize;
+        let scale = SCALE as usize;
+        let out_width = width * scale;
+        let out_height = self.size.height as usize * scale;
+        let stride = row_bytes(width);
+
+        let mut pixels = vec![0u8; out_width * out_height];
+        for y in 0..self.size.height as usize {
+            for x in 0..width {
+                let index = match self.target {
+                    TargetFormat::BinaryColor => {
+                        let bitmask = 1u8 << (7 - (x % 8));
+                        if buf[y * stride + x / 8] & bitmask != 0 { 1 } else { 0 }
+                    }
+                    TargetFormat::Gray4 => buf[y * width + x],
+                };
+                for dy in 0..scale {
+                    let row = (y * scale + dy) * out_width;
+                    for dx in 0..scale {
+                        pixels[row + x * scale + dx] = index;
+                    }
+                }
+            }
+        }
+
+        let mut frame = Frame::default();
+        frame.width = out_width as u16;
+        frame.height = out_height as u16;
+        frame.delay = self.delay;
+        frame.buffer = Cow::Owned(pixels);
+        self.encoder.write_frame(&frame)?;
+        Ok(())
+    }
+}
+
+/// Magic prefix of the self-describing header sent to every subscriber on connect,
+/// followed by the geometry and pixel format so a client can configure itself with no
+/// prior knowledge of the display.
+const SERVE_MAGIC: &[u8; 8] = b"OLEDVIEW";
+
+/// How many frames a subscriber may fall behind before it is considered slow and
+/// dropped, so a stalled client can never back-pressure the capture path.
+const SERVE_QUEUE: usize = 8;
+
+/// Broadcasts freshly decoded framebuffers to any connected TCP subscribers. Each client
+/// is served by its own writer thread fed through a bounded channel; a client that can't
+/// keep up is dropped rather than stalling capture, the same way a screencast source
+/// serves frames to subscribers without blocking the compositor.
+struct FrameServer {
+    clients: Arc<Mutex<Vec<SyncSender<Arc<Vec<u8>>>>>>,
+}
+
+impl FrameServer {
+    /// Bind `addr` and spawn the accept loop. Every new connection is first sent the
+    /// header describing `size`/`target`, then streamed length-prefixed raw frames.
+    fn bind(addr: &str, size: Size, target: TargetFormat) -> Result<Self, Box<dyn Error>> {
+        let listener = TcpListener::bind(addr)?;
+        let clients: Arc<Mutex<Vec<SyncSender<Arc<Vec<u8>>>>>> = Arc::new(Mutex::new(Vec::new()));
+
+        // Precompute the fixed header: magic, width, height and pixel format, all LE.
+        let mut header = Vec::with_capacity(SERVE_MAGIC.len() + 12);
+        header.extend_from_slice(SERVE_MAGIC);
+        header.extend_from_slice(&size.width.to_le_bytes());
+        header.extend_from_slice(&size.height.to_le_bytes());
+        let format: u32 = match target {
+            TargetFormat::BinaryColor => 0,
+            TargetFormat::Gray4 => 1,
+        };
+        header.extend_from_slice(&format.to_le_bytes());
+
+        let accept_clients = Arc::clone(&clients);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { continue };
+                if stream.write_all(&header).is_err() {
+                    continue;
+                }
+                let (tx, rx) = sync_channel::<Arc<Vec<u8>>>(SERVE_QUEUE);
+                accept_clients.lock().unwrap().push(tx);
+                thread::spawn(move || {
+                    for frame in rx {
+                        let len = (frame.len() as u32).to_le_bytes();
+                        if stream.write_all(&len).is_err() || stream.write_all(&frame).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+
+        Ok(Self { clients })
+    }
+
+    /// Broadcast one decoded frame to every subscriber, dropping any whose queue has
+    /// filled (a slow client) or whose connection has closed.
+    fn broadcast(&self, buf: &[u8]) {
+        let frame = Arc::new(buf.to_vec());
+        self.clients
+            .lock()
+            .unwrap()
+            .retain(|tx| match tx.try_send(Arc::clone(&frame)) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => false,
+            });
+    }
+}
+
+/// Appends every captured raw (pre-decode) framebuffer to a flat file with a relative
+/// timestamp, so a run can be played back later with `--replay`. Each record is
+/// `[u64 micros][u32 len][payload]`, little-endian, where `micros` is measured from the
+/// first recorded frame.
+struct FrameDumper {
+    writer: BufWriter<File>,
+    start: Option<Instant>,
+}
+
+impl FrameDumper {
+    fn new(path: &str) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            writer: BufWriter::new(File::create(path)?),
+            start: None,
+        })
+    }
+
+    /// Append one raw framebuffer, stamped with the elapsed time since the first frame.
+    fn push(&mut self, raw: &[u8]) -> Result<(), Box<dyn Error>> {
+        let start = *self.start.get_or_insert_with(Instant::now);
+        let micros = start.elapsed().as_micros() as u64;
+        self.writer.write_all(&micros.to_le_bytes())?;
+        self.writer.write_all(&(raw.len() as u32).to_le_bytes())?;
+        self.writer.write_all(raw)?;
+        Ok(())
+    }
+}
+
+/// Reads back a `--dump` file, yielding each raw framebuffer together with the delay that
+/// elapsed before it was captured, so playback matches the original timing.
+struct FrameReplay {
+    reader: BufReader<File>,
+    last: Option<u64>,
+}
+
+impl FrameReplay {
+    fn open(path: &str) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            reader: BufReader::new(File::open(path)?),
+            last: None,
+        })
+    }
+
+    /// Return the next `(delay_since_previous_frame, raw_bytes)`, or `None` at end of file.
+    fn next(&mut self) -> Option<(Duration, Vec<u8>)> {
+        let mut ts = [0u8; 8];
+        if self.reader.read_exact(&mut ts).is_err() {
+            return None;
+        }
+        let micros = u64::from_le_bytes(ts);
+        let mut len = [0u8; 4];
+        self.reader.read_exact(&mut len).ok()?;
+        let mut buf = vec![0u8; u32::from_le_bytes(len) as usize];
+        self.reader.read_exact(&mut buf).ok()?;
+        let delay = Duration::from_micros(micros - self.last.unwrap_or(micros));
+        self.last = Some(micros);
+        Some((delay, buf))
+    }
+}
+
+fn find_debug_symbol(nm: &str, elf: &str, symbol: &str) -> Result<u32, ErrorKind> {
     use std::process::Command;
 
-    let output = Command::new("arm-none-eabi-nm")
+    let output = Command::new(nm)
         .arg("-C")
-        .arg(ELF_PATH)
+        .arg(elf)
         .output()
         .expect("Could not run nm");
 
     let stdout = String::from_utf8_lossy(&output.stdout).to_string();
 
     for line in stdout.lines() {
-        if line.contains("OLED::oledCurrentImage") {
+        if line.contains(symbol) {
             let components: Vec<&str> = line.split_whitespace().collect();
             let hex_addr = components[0].trim_start_matches("0x");
             let addr = u32::from_str_radix(hex_addr, 16).unwrap();
@@ -79,94 +590,240 @@ fn cont(gdb: &mut GdbServer<BufReader<TcpStream>, TcpStream>) {
         .unwrap();
 }
 
+/// A resolved framebuffer the capture thread reads on every pass: its pointer symbol
+/// address, how many bytes to request, how to decode them, and where to publish.
+struct CaptureTarget {
+    addr: u32,
+    src_len: usize,
+    size: Size,
+    format: Box<dyn FramebufferFormat + Send>,
+    dst: Vec<u8>,
+    mailbox: Arc<Mutex<Option<Vec<u8>>>>,
+    server: Option<Arc<FrameServer>>,
+    dumper: Option<FrameDumper>,
+}
+
 fn main() -> Result<(), core::convert::Infallible> {
-    const DISPLAY_SIZE: Size = Size::new(128, 48);
-    const DISPLAY_BUF_SIZE: usize = (DISPLAY_SIZE.width * (DISPLAY_SIZE.height >> 3)) as usize;
+    let args = Args::parse();
+
+    // Duration between iterations in nanoseconds, from the desired refresh rate in Hz
+    let interval = Duration::from_nanos((1_000_000_000f64 / args.frequency) as u64);
+    // The GIF delay is in hundredths of a second, derived from the render interval.
+    let delay = ((interval.as_micros() + 5_000) / 10_000) as u16;
 
-    let display_buf_addr = find_debug_symbol().unwrap();
-    let mut display = SimulatorDisplay::<BinaryColor>::new(DISPLAY_SIZE);
+    // Resolve the displays to mirror, defaulting to the Deluge's main OLED.
+    let specs: Vec<DisplaySpec> = if args.displays.is_empty() {
+        vec![parse_display_spec("OLED::oledCurrentImage,128x48,ssd1306")]
+    } else {
+        args.displays.iter().map(|s| parse_display_spec(s)).collect()
+    };
 
-    let output_settings = OutputSettingsBuilder::new()
+    let running = Arc::new(AtomicBool::new(true));
+
+    // One set of output settings per target; `BinaryColor` keeps the `OledWhite` theme
+    // while `Gray4` renders its grayscale ramp directly.
+    let binary_settings = OutputSettingsBuilder::new()
         .theme(BinaryColorTheme::OledWhite)
-        .scale(4)
+        .scale(SCALE)
+        .pixel_spacing(0)
+        .build();
+    let gray_settings = OutputSettingsBuilder::new()
+        .scale(SCALE)
         .pixel_spacing(0)
         .build();
-    let mut window =
-        embedded_graphics_simulator::Window::new("Deluge OLED output", &output_settings);
-
-    // Try to open our stream to GDB and setup our protocol system
-    let mut gdb_stream = TcpStream::connect("127.0.0.1:3333").expect("failed to connect to GDB server");
-    let mut gdb = GdbServer::new(BufReader::new(gdb_stream.try_clone().unwrap()), gdb_stream);
 
-    // Desired update frequency in Hz
-    let frequency: f64 = 24.0;
+    // Build a window and render-side state per display, and a capture target the single
+    // GDB-reading thread walks each pass (OpenOCD only accepts one GDB connection).
+    let mut windows = Vec::new();
+    let mut lives = Vec::new();
+    let mut sizes = Vec::new();
+    let mut recorders = Vec::new();
+    let mut mailboxes = Vec::new();
+    let mut capture_targets = Vec::new();
+
+    for (i, spec) in specs.into_iter().enumerate() {
+        // In replay mode there is no live target to resolve; frames come from the dump.
+        let addr = if args.replay.is_some() {
+            0
+        } else {
+            find_debug_symbol(&args.nm, &args.elf, &spec.symbol)
+                .unwrap_or_else(|_| panic!("could not find symbol '{}'", spec.symbol))
+        };
+        let target = spec.format.target();
+        let src_len = spec.format.src_len(spec.size);
+        let dst_len = spec.format.dst_len(spec.size);
 
-    // Duration between iterations in nanoseconds
-    let interval = Duration::from_nanos((1_000_000_000f64 / frequency) as u64);
+        let settings = match target {
+            TargetFormat::BinaryColor => &binary_settings,
+            TargetFormat::Gray4 => &gray_settings,
+        };
+        let window = embedded_graphics_simulator::Window::new(&spec.symbol, settings);
+
+        // Only the primary display feeds the optional recording.
+        let recorder = if i == 0 {
+            args.record.as_ref().map(|path| {
+                GifRecorder::new(path, spec.size, target, delay).expect("failed to create GIF recorder")
+            })
+        } else {
+            None
+        };
 
-    // Store the start time of the loop
-    let mut last_time = Instant::now();
+        // Only the primary display is broadcast to subscribers.
+        let server = if i == 0 {
+            args.serve.as_ref().map(|addr| {
+                Arc::new(
+                    FrameServer::bind(addr, spec.size, target)
+                        .expect("failed to start frame server"),
+                )
+            })
+        } else {
+            None
+        };
 
-    let mut display_buf = [0; ((DISPLAY_SIZE.width * DISPLAY_SIZE.height) / 8) as usize];
+        // Only the primary display's raw frames are dumped, and never while replaying.
+        let dumper = if i == 0 && args.replay.is_none() {
+            args.dump
+                .as_ref()
+                .map(|path| FrameDumper::new(path).expect("failed to open dump file"))
+        } else {
+            None
+        };
 
-    loop {
-        //halt(&mut gdb);
+        let mailbox: Arc<Mutex<Option<Vec<u8>>>> = Arc::new(Mutex::new(None));
+
+        windows.push(window);
+        lives.push(LiveDisplay::new(target, spec.size));
+        sizes.push(spec.size);
+        recorders.push(recorder);
+        mailboxes.push(Arc::clone(&mailbox));
+        capture_targets.push(CaptureTarget {
+            addr,
+            src_len,
+            size: spec.size,
+            format: spec.format,
+            dst: vec![0u8; dst_len],
+            mailbox,
+            server,
+            dumper,
+        });
+    }
 
-        // Fetch the pointer result of OLED::oledCurrentImage
-        let current_image_buf_addr = read_u32(&mut gdb, display_buf_addr);
+    // Worker thread: either capture live frames over GDB, or replay a dump file. Both
+    // publish completed snapshots through the single-slot mailboxes the render loop
+    // consumes, so nothing downstream needs to know which source is feeding it.
+    let worker = if let Some(replay_path) = args.replay.clone() {
+        // Replay thread: feed the primary display from a dump file instead of GDB,
+        // sleeping out each frame's recorded delay so playback matches the capture.
+        let running = Arc::clone(&running);
+        let mut targets = capture_targets;
+        thread::spawn(move || {
+            let target = &mut targets[0];
+            let mut replay = FrameReplay::open(&replay_path).expect("failed to open replay file");
+            while running.load(Ordering::Relaxed) {
+                let Some((delay, raw)) = replay.next() else { break };
+                thread::sleep(delay);
+
+                // Run the recorded raw framebuffer through the same decode-and-render path.
+                target.format.decode(&raw, target.size, &mut target.dst);
+                if let Some(server) = &target.server {
+                    server.broadcast(&target.dst);
+                }
+                *target.mailbox.lock().unwrap() = Some(target.dst.clone());
+            }
+        })
+    } else {
+        // Capture thread: GDB reads, the framebuffer `m` request and the format decode all
+        // run here, as fast as GDB allows, publishing each completed snapshot through a
+        // single-slot mailbox. A slow `m` round trip never stalls rendering and rendering
+        // never holds up capture, mirroring the asynchronous-flush design of USB framebuffer
+        // drivers: a fast consumer never blocks on a slow producer.
+        let running = Arc::clone(&running);
+        let gdb_addr = args.gdb.clone();
+        let mut targets = capture_targets;
+        thread::spawn(move || {
+            // OpenOCD only accepts one GDB connection, so open it from the capture thread.
+            let gdb_stream = TcpStream::connect(&gdb_addr).expect("failed to connect to GDB server");
+            let mut gdb = GdbServer::new(BufReader::new(gdb_stream.try_clone().unwrap()), gdb_stream);
+            while running.load(Ordering::Relaxed) {
+                for target in targets.iter_mut() {
+                    //halt(&mut gdb);
+
+                    // Fetch the pointer to this display's current framebuffer
+                    let current_image_buf_addr = read_u32(&mut gdb, target.addr);
+
+                    // Read the framebuffer from the target by requesting the block of RAM from GDB
+                    let request = format!("m{:x},{:x}", current_image_buf_addr, target.src_len);
+                    gdb.dispatch(&CheckedPacket::from_data(Kind::Packet, request.into()))
+                        .unwrap();
+
+                    // Decode the received hex string into a bytes
+                    let decoded: Vec<u8> = match gdb.next_packet().unwrap() {
+                        Some(p) => {
+                            let data = p.invalidate_check().data;
+                            let bytes = hex::decode(data).expect("failed to decode display buffer read response");
+                            bytes.into_iter().map(|b| u8::from_le(b)).collect()
+                        }
+                        None => Vec::new(),
+                    };
+
+                    //cont(&mut gdb);
+
+                    // Append the raw framebuffer to the dump, if one was requested
+                    if let Some(dumper) = target.dumper.as_mut() {
+                        dumper.push(&decoded).expect("failed to write dump frame");
+                    }
 
+                    // Unpack the controller's framebuffer into the render-ready buffer
+                    target.format.decode(&decoded, target.size, &mut target.dst);
 
-        // Read the framebuffer from the deluge by requesting the full block of RAM from GDB
-        let request = format!("m{:x},{:x}", current_image_buf_addr, DISPLAY_BUF_SIZE);
-        gdb.dispatch(&CheckedPacket::from_data(Kind::Packet, request.into()))
-            .unwrap();
+                    // Fan the freshly decoded frame out to any TCP subscribers.
+                    if let Some(server) = &target.server {
+                        server.broadcast(&target.dst);
+                    }
 
-        // Decode the received hex string into a bytes
-        let decoded = match gdb.next_packet().unwrap() {
-            Some(p) => {
-                let data = p.invalidate_check().data;
-                let bytes = hex::decode(data).expect("failed to decode display buffer read response");
-                bytes.into_iter().map(|b| u8::from_le(b)).collect()
+                    // Publish the freshest frame, overwriting any snapshot not yet consumed.
+                    *target.mailbox.lock().unwrap() = Some(target.dst.clone());
+                }
             }
-            None => Vec::new(),
-        };
+        })
+    };
 
-        //cont(&mut gdb);
+    // Render loop: one steady-rate pass drives every window from its freshest frame.
+    let mut last_time = Instant::now();
+    let mut frames: Vec<Option<Vec<u8>>> = vec![None; windows.len()];
 
-        // Image decode ----------------------------------------------------
+    loop {
+        for i in 0..windows.len() {
+            // Consume the freshest captured frame, keeping the previous one if none is ready
+            if let Some(latest) = mailboxes[i].lock().unwrap().take() {
+                frames[i] = Some(latest);
+            }
 
-        // The Deluge (and most SSD1306/SSD1309 display drivers) use a byte-packed column-first page system for storing data
-        // The LSB of byte 0 is (0,0), the MSB of byte 0 is (0,7), byte 1 corresponds to (1, x), etc.
+            if let Some(buf) = frames[i].as_deref() {
+                lives[i].draw(sizes[i], buf);
 
-        for (page_y, row) in decoded.chunks(DISPLAY_SIZE.width as usize).enumerate() {
-            for (x, col) in row.into_iter().enumerate() {
-                for bit in 0..8 {
-                    let y = (page_y * 8) + bit;
-                    let buf_idx = ((y * DISPLAY_SIZE.width as usize) + x) / 8;
-                    let bitmask = 1u8 << (7 - (x % 8));
-                    if (col >> bit) & 0b1 == 1 {
-                        display_buf[buf_idx] |= bitmask
-                    } else {
-                        display_buf[buf_idx] &= !bitmask
-                    }
+                // Encode this frame into the recording, if one was requested
+                if let Some(recorder) = recorders[i].as_mut() {
+                    recorder.push(buf).expect("failed to write GIF frame");
                 }
             }
-        }
-        
-        // Display update ---------------------------------------------------------------
-
-        // Generate a "raw" image from our converted display buffer
-        let raw_image = ImageRaw::<BinaryColor>::new(&display_buf, DISPLAY_SIZE.width);
-        let image = Image::new(&raw_image, Point::zero());
-
-        // Draw the newly generated image on the display
-        image.draw(&mut display)?;
 
-        // Update our window with the display
-        window.update(&display);
+            lives[i].update(&mut windows[i]);
+        }
 
-        // Check if a exit has been requested (such as clicking the window exit button)
-        if window.events().any(|e| e == SimulatorEvent::Quit) {
+        // Quit if any window requested it; drain events on every window regardless.
+        let mut quit = false;
+        for window in windows.iter_mut() {
+            if window.events().any(|e| e == SimulatorEvent::Quit) {
+                quit = true;
+            }
+        }
+        if quit {
+            // Signal the capture thread to wind down and flush the GIF trailers cleanly
+            running.store(false, Ordering::Relaxed);
+            for recorder in recorders.iter_mut() {
+                drop(recorder.take());
+            }
             break;
         }
 
@@ -183,5 +840,8 @@ fn main() -> Result<(), core::convert::Infallible> {
         last_time = Instant::now();
     }
 
+    // Let the worker thread observe the shutdown flag and exit cleanly
+    worker.join().ok();
+
     Ok(())
 }